@@ -1,34 +1,146 @@
 use anyhow::Result;
-use core::fmt;
-use dashmap::DashMap;
-use futures::{stream::SplitStream, SinkExt, StreamExt};
-use std::{net::SocketAddr, sync::Arc};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    error::DatabaseError,
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    Row,
+};
+use std::{collections::HashSet, net::SocketAddr, str::FromStr, sync::Arc, time::Instant};
+use thiserror::Error;
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{mpsc, watch},
+    task::JoinSet,
 };
+use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage, WebSocketStream};
 use tokio_util::codec::{Framed, LinesCodec};
 use tracing::{info, level_filters::LevelFilter, warn};
 use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
 
 const MAX_MESSAGE: usize = 128;
+const DB_URL: &str = "sqlite://chat.db";
+
+type WsStream = WebSocketStream<TcpStream>;
 
-#[derive(Debug, Default)]
+type RoomId = String;
+/// A peer is identified by its authenticated user id, not its socket address,
+/// so the same account can drop and reconnect without losing its identity.
+type UserId = i64;
+
+#[derive(Debug)]
 struct AppState {
-    peers: DashMap<SocketAddr, mpsc::Sender<Arc<Message>>>,
+    db: SqlitePool,
+    peers: DashMap<UserId, mpsc::Sender<Arc<Message>>>,
+    /// Room membership, keyed by room name (including the leading `#`).
+    rooms: DashMap<RoomId, HashSet<UserId>>,
+    /// Reverse index of which rooms a given peer currently belongs to.
+    memberships: DashMap<UserId, HashSet<RoomId>>,
+    /// Presence info for connected peers, for WHOIS/WHO introspection.
+    peer_info: DashMap<UserId, PeerInfo>,
+    /// Reverse index from username to the connected peer's id.
+    usernames: DashMap<String, UserId>,
 }
 
 #[derive(Debug)]
 struct Peer {
+    id: UserId,
     username: String,
-    stream: SplitStream<Framed<TcpStream, LinesCodec>>,
 }
 
 #[derive(Debug)]
+struct PeerInfo {
+    username: String,
+    addr: SocketAddr,
+    connected_at: Instant,
+}
+
+#[derive(Debug, Error)]
+enum AppError {
+    #[error("username already taken")]
+    UsernameTaken,
+    #[error("invalid username or password")]
+    InvalidCredentials,
+    #[error("account already connected")]
+    AlreadyConnected,
+    #[error("database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("password hashing error: {0}")]
+    Hash(#[from] argon2::password_hash::Error),
+}
+
+/// Requests a client may send, one JSON object per text frame.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestKind {
+    Register { username: String, password: String },
+    Authenticate { username: String, password: String },
+    Join { room: RoomId },
+    Chat { room: RoomId, message: String },
+    Leave { room: RoomId },
+    Whois { username: String },
+    Who { room: RoomId },
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestContainer {
+    #[serde(flatten)]
+    kind: RequestKind,
+}
+
+/// Direct acknowledgement sent back to the peer that issued a request.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseKind {
+    Registered { id: UserId },
+    Authenticated { id: UserId },
+    Joined { id: UserId, room: RoomId },
+    Parted { id: UserId, room: RoomId },
+    Whois(WhoisInfo),
+    Who { room: RoomId, members: Vec<String> },
+    Error { reason: String },
+}
+
+/// Presence details returned by a `/whois` query.
+#[derive(Debug, Serialize)]
+struct WhoisInfo {
+    username: String,
+    addr: SocketAddr,
+    rooms: Vec<RoomId>,
+    connected_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ResponseContainer {
+    #[serde(flatten)]
+    kind: ResponseKind,
+}
+
+/// Events broadcast to the other members of a room.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 enum Message {
-    UserJoined(String),
-    UserLeft(String),
-    Chat { name: String, message: String },
+    UserJoined {
+        id: UserId,
+        username: String,
+        room: RoomId,
+    },
+    UserLeft {
+        id: UserId,
+        username: String,
+        room: RoomId,
+    },
+    Chat {
+        id: UserId,
+        username: String,
+        room: RoomId,
+        message: String,
+    },
 }
 
 #[tokio::main]
@@ -36,122 +148,733 @@ async fn main() -> Result<()> {
     let layer = Layer::new().with_filter(LevelFilter::INFO);
     tracing_subscriber::registry().with(layer).init();
 
-    let addr = "0.0.0.0:8080";
-    let listener = TcpListener::bind(addr).await?;
-    info!("Listening on: {}", addr);
-    let state = Arc::new(AppState::default());
+    let state = Arc::new(AppState::try_new(DB_URL).await?);
+    info!("Connected to database {}", DB_URL);
 
-    loop {
-        let (stream, raddr) = listener.accept().await?;
-        info!("Accepted connection from: {}", raddr);
-        let state_cloned = state.clone();
-        tokio::spawn(async move {
-            if let Err(err) = handle_client(raddr, stream, state_cloned).await {
-                warn!("Error handling client {}: {:?}", raddr, err);
+    let raw_addr = "0.0.0.0:8080";
+    let ws_addr = "0.0.0.0:8081";
+
+    let raw_listener = TcpListener::bind(raw_addr).await?;
+    info!("Listening for raw-line clients on: {}", raw_addr);
+
+    let ws_listener = TcpListener::bind(ws_addr).await?;
+    info!("Listening for WebSocket clients on: {}", ws_addr);
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let raw_state = state.clone();
+    let raw_shutdown = shutdown_rx.clone();
+    let raw_task = tokio::spawn(async move {
+        let mut shutdown = raw_shutdown.clone();
+        let mut clients = JoinSet::new();
+        loop {
+            tokio::select! {
+                conn = raw_listener.accept() => {
+                    let (stream, raddr) = match conn {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            warn!("Error accepting raw connection: {:?}", err);
+                            continue;
+                        }
+                    };
+                    info!("Accepted raw connection from: {}", raddr);
+                    let state_cloned = raw_state.clone();
+                    let shutdown_cloned = raw_shutdown.clone();
+                    clients.spawn(async move {
+                        if let Err(err) = handle_raw_client(raddr, stream, state_cloned, shutdown_cloned).await {
+                            warn!("Error handling raw client {}: {:?}", raddr, err);
+                        }
+                    });
+                }
+                _ = shutdown.changed() => {
+                    info!("Raw-line listener shutting down");
+                    break;
+                }
             }
-        });
-    }
+        }
+        info!("Draining {} in-flight raw-line connections", clients.len());
+        while let Some(result) = clients.join_next().await {
+            if let Err(err) = result {
+                warn!("Raw client task panicked: {:?}", err);
+            }
+        }
+    });
+
+    let ws_state = state.clone();
+    let ws_shutdown = shutdown_rx.clone();
+    let ws_task = tokio::spawn(async move {
+        let mut shutdown = ws_shutdown.clone();
+        let mut clients = JoinSet::new();
+        loop {
+            tokio::select! {
+                conn = ws_listener.accept() => {
+                    let (stream, raddr) = match conn {
+                        Ok(conn) => conn,
+                        Err(err) => {
+                            warn!("Error accepting WebSocket connection: {:?}", err);
+                            continue;
+                        }
+                    };
+                    info!("Accepted WebSocket connection from: {}", raddr);
+                    let state_cloned = ws_state.clone();
+                    let shutdown_cloned = ws_shutdown.clone();
+                    clients.spawn(async move {
+                        if let Err(err) = handle_ws_client(raddr, stream, state_cloned, shutdown_cloned).await {
+                            warn!("Error handling WebSocket client {}: {:?}", raddr, err);
+                        }
+                    });
+                }
+                _ = shutdown.changed() => {
+                    info!("WebSocket listener shutting down");
+                    break;
+                }
+            }
+        }
+        info!("Draining {} in-flight WebSocket connections", clients.len());
+        while let Some(result) = clients.join_next().await {
+            if let Err(err) = result {
+                warn!("WebSocket client task panicked: {:?}", err);
+            }
+        }
+    });
+
+    tokio::signal::ctrl_c().await?;
+    info!("Received Ctrl+C, shutting down");
+    let _ = shutdown_tx.send(true);
+
+    let _ = tokio::try_join!(raw_task, ws_task)?;
+    state.shutdown();
 
-    #[allow(unreachable_code)]
     Ok(())
 }
 
-async fn handle_client(addr: SocketAddr, stream: TcpStream, state: Arc<AppState>) -> Result<()> {
+async fn handle_raw_client(
+    addr: SocketAddr,
+    stream: TcpStream,
+    state: Arc<AppState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
     let mut lines = Framed::new(stream, LinesCodec::new());
-    lines.send("Please enter your username:").await?;
-
-    // TODO: 循环读取名字，直到不为空以及名字重复问题
-    let username = match lines.next().await {
-        Some(Ok(line)) => {
-            if line.trim().is_empty() {
-                lines.send("Empty username error!!!").await?;
-                return Err(anyhow::anyhow!("Empty username"));
-            } else {
-                line
+    lines
+        .send("Please /register <username> <password> or /login <username> <password>")
+        .await?;
+
+    let (mut stream_sender, mut lines_receiver) = lines.split();
+    let mut peer: Option<Peer> = None;
+    let mut stream_receiver: Option<mpsc::Receiver<Arc<Message>>> = None;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                let _ = stream_sender.send("Server is shutting down, goodbye!".to_string()).await;
+                break;
+            }
+            line = lines_receiver.next() => {
+                let line = match line {
+                    Some(Ok(line)) => line,
+                    _ => break,
+                };
+
+                match parse_command(&line) {
+                    Command::Register(username, password) => {
+                        match state.register(&username, &password).await {
+                            Ok(id) => {
+                                let _ = stream_sender
+                                    .send(format!("Registered as {username} (id {id}), now /login"))
+                                    .await;
+                            }
+                            Err(err) => {
+                                let _ = stream_sender.send(format!("Register failed: {err}")).await;
+                            }
+                        }
+                    }
+                    Command::Login(username, password) => {
+                        match state.authenticate(&username, &password).await {
+                            Ok(id) => match state.connect(id, &username, addr) {
+                                Ok(receiver) => {
+                                    peer = Some(Peer { id, username });
+                                    stream_receiver = Some(receiver);
+                                    let _ = stream_sender.send("Logged in".to_string()).await;
+                                }
+                                Err(err) => {
+                                    let _ =
+                                        stream_sender.send(format!("Login failed: {err}")).await;
+                                }
+                            },
+                            Err(err) => {
+                                let _ = stream_sender.send(format!("Login failed: {err}")).await;
+                            }
+                        }
+                    }
+                    Command::Join(room) if peer.is_none() => {
+                        let _ = stream_sender.send("You must /login first".to_string()).await;
+                        let _ = room;
+                    }
+                    Command::Part(room) if peer.is_none() => {
+                        let _ = stream_sender.send("You must /login first".to_string()).await;
+                        let _ = room;
+                    }
+                    Command::Msg(room, text) if peer.is_none() => {
+                        let _ = stream_sender.send("You must /login first".to_string()).await;
+                        let _ = (room, text);
+                    }
+                    Command::Join(room) => {
+                        let peer = peer.as_ref().expect("checked above");
+                        state.join_room(peer.id, &room);
+                        let message = Arc::new(Message::UserJoined {
+                            id: peer.id,
+                            username: peer.username.clone(),
+                            room: room.clone(),
+                        });
+                        info!("{:?}", message);
+                        state.broadcast_to_room(&room, peer.id, message).await;
+                    }
+                    Command::Part(room) => {
+                        let peer = peer.as_ref().expect("checked above");
+                        state.part_room(peer.id, &room);
+                        let message = Arc::new(Message::UserLeft {
+                            id: peer.id,
+                            username: peer.username.clone(),
+                            room: room.clone(),
+                        });
+                        info!("{:?}", message);
+                        state.broadcast_to_room(&room, peer.id, message).await;
+                    }
+                    Command::Msg(room, text) => {
+                        let peer = peer.as_ref().expect("checked above");
+                        if !state.is_member(peer.id, &room) {
+                            let _ = stream_sender
+                                .send(format!("You must /join {room} before messaging it"))
+                                .await;
+                            continue;
+                        }
+                        let message = Arc::new(Message::Chat {
+                            id: peer.id,
+                            username: peer.username.clone(),
+                            room: room.clone(),
+                            message: text,
+                        });
+                        info!("{:?}", message);
+                        state.broadcast_to_room(&room, peer.id, message).await;
+                    }
+                    Command::Whois(username) if peer.is_none() => {
+                        let _ = stream_sender.send("You must /login first".to_string()).await;
+                        let _ = username;
+                    }
+                    Command::Who(room) if peer.is_none() => {
+                        let _ = stream_sender.send("You must /login first".to_string()).await;
+                        let _ = room;
+                    }
+                    Command::Whois(username) => {
+                        match state.whois(&username) {
+                            Some(info) => {
+                                let _ = stream_sender
+                                    .send(format!(
+                                        "{} is at {}, connected {}s, in rooms: {}",
+                                        info.username,
+                                        info.addr,
+                                        info.connected_secs,
+                                        info.rooms.join(", ")
+                                    ))
+                                    .await;
+                            }
+                            None => {
+                                let _ = stream_sender
+                                    .send(format!("No such user online: {username}"))
+                                    .await;
+                            }
+                        }
+                    }
+                    Command::Who(room) => {
+                        let members = state.who(&room);
+                        let _ = stream_sender
+                            .send(format!("{room}: {}", members.join(", ")))
+                            .await;
+                    }
+                    Command::Unsupported => {
+                        let _ = stream_sender
+                            .send("Unknown command, try /join #room, /part #room, /msg #room <text>, /whois <username> or /who #room".to_string())
+                            .await;
+                    }
+                }
+            }
+            message = async {
+                match stream_receiver.as_mut() {
+                    Some(receiver) => receiver.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some(message) = message else { break };
+                lines_sender_forward(&mut stream_sender, &message).await;
             }
         }
-        _ => {
-            warn!("Failed to read username from client: {}", addr);
-            return Err(anyhow::anyhow!("Failed to read username"));
+    }
+
+    if let Some(peer) = peer {
+        info!("Raw client {} ({}) disconnected", addr, peer.username);
+        for room in state.disconnect(peer.id) {
+            let message = Arc::new(Message::UserLeft {
+                id: peer.id,
+                username: peer.username.clone(),
+                room: room.clone(),
+            });
+            info!("{:?}", message);
+            state.broadcast_to_room(&room, peer.id, message).await;
         }
-    };
+    } else {
+        info!("Raw client {} disconnected before logging in", addr);
+    }
+
+    Ok(())
+}
+
+async fn lines_sender_forward(
+    sender: &mut futures::stream::SplitSink<Framed<TcpStream, LinesCodec>, String>,
+    message: &Message,
+) {
+    if let Err(err) = sender.send(format_message(message)).await {
+        warn!("Error forwarding message to raw client: {:?}", err);
+    }
+}
+
+/// Renders a broadcast `Message` as a human-readable line, for raw-line
+/// clients (e.g. telnet) that don't speak the WebSocket listener's JSON.
+fn format_message(message: &Message) -> String {
+    match message {
+        Message::UserJoined { username, room, .. } => format!("* {username} joined {room}"),
+        Message::UserLeft { username, room, .. } => format!("* {username} left {room}"),
+        Message::Chat {
+            username,
+            room,
+            message,
+            ..
+        } => format!("[{room}] {username}: {message}"),
+    }
+}
 
-    let mut peer = state.add(addr, username, lines).await;
+async fn handle_ws_client(
+    addr: SocketAddr,
+    stream: TcpStream,
+    state: Arc<AppState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let ws_stream: WsStream = accept_async(stream).await?;
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    let message = Arc::new(Message::UserJoined(peer.username.clone()));
-    info!("{}", message);
-    state.broadcast(addr, message).await;
+    let mut peer: Option<Peer> = None;
+    let mut stream_receiver: Option<mpsc::Receiver<Arc<Message>>> = None;
 
-    while let Some(line) = peer.stream.next().await {
-        let line = match line {
-            Ok(line) => line,
-            Err(err) => {
-                warn!("Error reading line from client {}: {:?}", addr, err);
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => {
+                let _ = ws_sender
+                    .send(WsMessage::Text("server shutting down".to_string()))
+                    .await;
+                let _ = ws_sender.close().await;
                 break;
             }
-        };
+            frame = ws_receiver.next() => {
+                let frame = match frame {
+                    Some(Ok(frame)) => frame,
+                    _ => break,
+                };
+                let text = match frame {
+                    WsMessage::Text(text) => text,
+                    WsMessage::Close(_) => break,
+                    _ => continue,
+                };
+
+                let request: RequestContainer = match serde_json::from_str(&text) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        send_response(&mut ws_sender, ResponseKind::Error { reason: err.to_string() }).await;
+                        continue;
+                    }
+                };
 
-        let message = Arc::new(Message::Chat {
-            name: peer.username.clone(),
-            message: line,
-        });
-        info!("{}", message);
-        state.broadcast(addr, message).await;
+                match request.kind {
+                    RequestKind::Register { username, password } => {
+                        match state.register(&username, &password).await {
+                            Ok(id) => send_response(&mut ws_sender, ResponseKind::Registered { id }).await,
+                            Err(err) => {
+                                send_response(&mut ws_sender, ResponseKind::Error { reason: err.to_string() }).await
+                            }
+                        }
+                    }
+                    RequestKind::Authenticate { username, password } => {
+                        match state.authenticate(&username, &password).await {
+                            Ok(id) => match state.connect(id, &username, addr) {
+                                Ok(receiver) => {
+                                    peer = Some(Peer { id, username });
+                                    stream_receiver = Some(receiver);
+                                    send_response(&mut ws_sender, ResponseKind::Authenticated { id }).await;
+                                }
+                                Err(err) => {
+                                    send_response(&mut ws_sender, ResponseKind::Error { reason: err.to_string() }).await
+                                }
+                            },
+                            Err(err) => {
+                                send_response(&mut ws_sender, ResponseKind::Error { reason: err.to_string() }).await
+                            }
+                        }
+                    }
+                    RequestKind::Join { room } => {
+                        let Some(peer) = peer.as_ref() else {
+                            send_response(&mut ws_sender, ResponseKind::Error { reason: "not authenticated".into() }).await;
+                            continue;
+                        };
+                        state.join_room(peer.id, &room);
+                        let message = Arc::new(Message::UserJoined {
+                            id: peer.id,
+                            username: peer.username.clone(),
+                            room: room.clone(),
+                        });
+                        state.broadcast_to_room(&room, peer.id, message).await;
+                        send_response(&mut ws_sender, ResponseKind::Joined { id: peer.id, room }).await;
+                    }
+                    RequestKind::Chat { room, message } => {
+                        let Some(peer) = peer.as_ref() else {
+                            send_response(&mut ws_sender, ResponseKind::Error { reason: "not authenticated".into() }).await;
+                            continue;
+                        };
+                        if !state.is_member(peer.id, &room) {
+                            send_response(&mut ws_sender, ResponseKind::Error { reason: format!("not a member of {room}") }).await;
+                            continue;
+                        }
+                        let message = Arc::new(Message::Chat {
+                            id: peer.id,
+                            username: peer.username.clone(),
+                            room: room.clone(),
+                            message,
+                        });
+                        state.broadcast_to_room(&room, peer.id, message).await;
+                    }
+                    RequestKind::Leave { room } => {
+                        let Some(peer) = peer.as_ref() else {
+                            send_response(&mut ws_sender, ResponseKind::Error { reason: "not authenticated".into() }).await;
+                            continue;
+                        };
+                        state.part_room(peer.id, &room);
+                        let message = Arc::new(Message::UserLeft {
+                            id: peer.id,
+                            username: peer.username.clone(),
+                            room: room.clone(),
+                        });
+                        state.broadcast_to_room(&room, peer.id, message).await;
+                        send_response(&mut ws_sender, ResponseKind::Parted { id: peer.id, room }).await;
+                    }
+                    RequestKind::Whois { username } => {
+                        if peer.is_none() {
+                            send_response(&mut ws_sender, ResponseKind::Error { reason: "not authenticated".into() }).await;
+                            continue;
+                        }
+                        match state.whois(&username) {
+                            Some(info) => send_response(&mut ws_sender, ResponseKind::Whois(info)).await,
+                            None => {
+                                send_response(&mut ws_sender, ResponseKind::Error { reason: format!("no such user online: {username}") }).await
+                            }
+                        }
+                    }
+                    RequestKind::Who { room } => {
+                        if peer.is_none() {
+                            send_response(&mut ws_sender, ResponseKind::Error { reason: "not authenticated".into() }).await;
+                            continue;
+                        }
+                        let members = state.who(&room);
+                        send_response(&mut ws_sender, ResponseKind::Who { room, members }).await;
+                    }
+                }
+            }
+            message = async {
+                match stream_receiver.as_mut() {
+                    Some(receiver) => receiver.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let Some(message) = message else { break };
+                let text = serde_json::to_string(&*message).unwrap_or_default();
+                if let Err(err) = ws_sender.send(WsMessage::Text(text)).await {
+                    warn!("Error sending message to {}: {:?}", addr, err);
+                    break;
+                }
+            }
+        }
     }
 
-    state.peers.remove(&addr);
-    let message = Arc::new(Message::UserLeft(peer.username.clone()));
-    info!("{}", message);
-    state.broadcast(addr, message).await;
+    if let Some(peer) = peer {
+        for room in state.disconnect(peer.id) {
+            let message = Arc::new(Message::UserLeft {
+                id: peer.id,
+                username: peer.username.clone(),
+                room: room.clone(),
+            });
+            state.broadcast_to_room(&room, peer.id, message).await;
+        }
+    }
 
     Ok(())
 }
 
+async fn send_response(
+    sender: &mut futures::stream::SplitSink<WsStream, WsMessage>,
+    kind: ResponseKind,
+) {
+    let container = ResponseContainer { kind };
+    if let Ok(text) = serde_json::to_string(&container) {
+        let _ = sender.send(WsMessage::Text(text)).await;
+    }
+}
+
 impl AppState {
-    async fn broadcast(&self, addr: SocketAddr, message: Arc<Message>) {
-        for peer in self.peers.iter() {
-            if peer.key() == &addr {
+    async fn broadcast_to_room(&self, room: &str, from_id: UserId, message: Arc<Message>) {
+        let Some(members) = self.rooms.get(room) else {
+            return;
+        };
+        for &member_id in members.iter() {
+            if member_id == from_id {
                 continue;
             }
-            if let Err(err) = peer.value().send(message.clone()).await {
-                warn!("Error sending message to {}: {:?}", addr, err);
+            if let Some(sender) = self.peers.get(&member_id) {
+                if let Err(err) = sender.send(message.clone()).await {
+                    warn!("Error sending message to peer {}: {:?}", member_id, err);
+                }
             }
         }
     }
 
-    async fn add(
-        &self,
-        addr: SocketAddr,
-        username: String,
-        stream: Framed<TcpStream, LinesCodec>,
-    ) -> Peer {
-        let (tx, mut rx) = mpsc::channel(MAX_MESSAGE);
-        self.peers.insert(addr, tx);
+    fn join_room(&self, id: UserId, room: &str) {
+        self.rooms.entry(room.to_string()).or_default().insert(id);
+        self.memberships
+            .entry(id)
+            .or_default()
+            .insert(room.to_string());
+    }
 
-        let (mut stream_sender, stream_receiver) = stream.split();
+    fn part_room(&self, id: UserId, room: &str) {
+        if let Some(mut members) = self.rooms.get_mut(room) {
+            members.remove(&id);
+        }
+        if let Some(mut joined) = self.memberships.get_mut(&id) {
+            joined.remove(room);
+        }
+    }
 
-        tokio::spawn(async move {
-            while let Some(message) = rx.recv().await {
-                if let Err(err) = stream_sender.send(message.to_string()).await {
-                    warn!("Error sending message to {}: {:?}", addr, err);
-                    break;
+    fn is_member(&self, id: UserId, room: &str) -> bool {
+        self.memberships
+            .get(&id)
+            .is_some_and(|rooms| rooms.contains(room))
+    }
+
+    fn part_all_rooms(&self, id: UserId) -> HashSet<RoomId> {
+        let Some((_, rooms)) = self.memberships.remove(&id) else {
+            return HashSet::new();
+        };
+        for room in &rooms {
+            if let Some(mut members) = self.rooms.get_mut(room) {
+                members.remove(&id);
+            }
+        }
+        rooms
+    }
+
+    async fn try_new(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let db = SqlitePool::connect_with(options).await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                username TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&db)
+        .await?;
+
+        Ok(Self {
+            db,
+            peers: DashMap::new(),
+            rooms: DashMap::new(),
+            memberships: DashMap::new(),
+            peer_info: DashMap::new(),
+            usernames: DashMap::new(),
+        })
+    }
+
+    async fn register(&self, username: &str, password: &str) -> Result<UserId, AppError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string();
+
+        sqlx::query("INSERT INTO users (username, password_hash) VALUES (?1, ?2)")
+            .bind(username)
+            .bind(&password_hash)
+            .execute(&self.db)
+            .await
+            .map(|result| result.last_insert_rowid())
+            .map_err(|err| match &err {
+                sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                    AppError::UsernameTaken
                 }
+                _ => AppError::Sqlx(err),
+            })
+    }
+
+    async fn authenticate(&self, username: &str, password: &str) -> Result<UserId, AppError> {
+        let row = sqlx::query("SELECT id, password_hash FROM users WHERE username = ?1")
+            .bind(username)
+            .fetch_optional(&self.db)
+            .await?
+            .ok_or(AppError::InvalidCredentials)?;
+
+        let id: UserId = row.try_get("id")?;
+        let password_hash: String = row.try_get("password_hash")?;
+
+        let parsed_hash = PasswordHash::new(&password_hash)?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .map_err(|_| AppError::InvalidCredentials)?;
+
+        Ok(id)
+    }
+
+    /// Atomically registers a peer's session, rejecting a second concurrent
+    /// login for an already-connected account instead of racing a
+    /// check-then-act across an `.await` and silently overwriting it.
+    fn connect(
+        &self,
+        id: UserId,
+        username: &str,
+        addr: SocketAddr,
+    ) -> Result<mpsc::Receiver<Arc<Message>>, AppError> {
+        let (tx, rx) = mpsc::channel(MAX_MESSAGE);
+        match self.peers.entry(id) {
+            Entry::Occupied(_) => return Err(AppError::AlreadyConnected),
+            Entry::Vacant(entry) => {
+                entry.insert(tx);
             }
-        });
+        }
+        self.usernames.insert(username.to_string(), id);
+        self.peer_info.insert(
+            id,
+            PeerInfo {
+                username: username.to_string(),
+                addr,
+                connected_at: Instant::now(),
+            },
+        );
+        Ok(rx)
+    }
 
-        Peer {
-            username,
-            stream: stream_receiver,
+    /// Tears down a peer's session: its send half, presence info, and room memberships.
+    fn disconnect(&self, id: UserId) -> HashSet<RoomId> {
+        self.peers.remove(&id);
+        if let Some((_, info)) = self.peer_info.remove(&id) {
+            self.usernames.remove(&info.username);
         }
+        self.part_all_rooms(id)
+    }
+
+    /// Looks up presence info for a username, for a `/whois` query.
+    fn whois(&self, username: &str) -> Option<WhoisInfo> {
+        let id = *self.usernames.get(username)?;
+        let info = self.peer_info.get(&id)?;
+        let rooms = self
+            .memberships
+            .get(&id)
+            .map(|rooms| rooms.iter().cloned().collect())
+            .unwrap_or_default();
+
+        Some(WhoisInfo {
+            username: info.username.clone(),
+            addr: info.addr,
+            rooms,
+            connected_secs: info.connected_at.elapsed().as_secs(),
+        })
+    }
+
+    /// Lists the usernames currently joined to a room, for a `/who` query.
+    fn who(&self, room: &str) -> Vec<String> {
+        let Some(members) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        members
+            .iter()
+            .filter_map(|id| self.peer_info.get(id).map(|info| info.username.clone()))
+            .collect()
+    }
+
+    /// Drops every peer's send half, disconnecting all connected clients.
+    fn shutdown(&self) {
+        self.peers.clear();
+        self.rooms.clear();
+        self.memberships.clear();
+        self.peer_info.clear();
+        self.usernames.clear();
     }
 }
 
-impl fmt::Display for Message {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Message::UserJoined(username) => write!(f, "[{}] has joined the chat", username),
-            Message::UserLeft(username) => write!(f, "[{}] has left the chat", username),
-            Message::Chat { name, message } => write!(f, "[{}]: {}", name, message),
-        }
+/// An IRC-style command parsed from a raw-line client's input.
+enum Command {
+    Register(String, String),
+    Login(String, String),
+    Join(RoomId),
+    Part(RoomId),
+    Msg(RoomId, String),
+    Whois(String),
+    Who(RoomId),
+    Unsupported,
+}
+
+fn parse_command(line: &str) -> Command {
+    let mut parts = line.splitn(2, ' ');
+    match parts.next() {
+        Some("/register") => match parse_credentials(parts.next()) {
+            Some((username, password)) => Command::Register(username, password),
+            None => Command::Unsupported,
+        },
+        Some("/login") => match parse_credentials(parts.next()) {
+            Some((username, password)) => Command::Login(username, password),
+            None => Command::Unsupported,
+        },
+        Some("/join") => match parts.next() {
+            Some(room) if !room.trim().is_empty() => Command::Join(room.trim().to_string()),
+            _ => Command::Unsupported,
+        },
+        Some("/part") => match parts.next() {
+            Some(room) if !room.trim().is_empty() => Command::Part(room.trim().to_string()),
+            _ => Command::Unsupported,
+        },
+        Some("/msg") => match parts.next() {
+            Some(rest) => match rest.trim().split_once(' ') {
+                Some((room, message)) if !room.is_empty() && !message.trim().is_empty() => {
+                    Command::Msg(room.to_string(), message.trim().to_string())
+                }
+                _ => Command::Unsupported,
+            },
+            None => Command::Unsupported,
+        },
+        Some("/whois") => match parts.next() {
+            Some(username) if !username.trim().is_empty() => {
+                Command::Whois(username.trim().to_string())
+            }
+            _ => Command::Unsupported,
+        },
+        Some("/who") => match parts.next() {
+            Some(room) if !room.trim().is_empty() => Command::Who(room.trim().to_string()),
+            _ => Command::Unsupported,
+        },
+        _ => Command::Unsupported,
+    }
+}
+
+fn parse_credentials(rest: Option<&str>) -> Option<(String, String)> {
+    let (username, password) = rest?.trim().split_once(' ')?;
+    if username.is_empty() || password.trim().is_empty() {
+        return None;
     }
+    Some((username.to_string(), password.trim().to_string()))
 }